@@ -3,25 +3,25 @@
 
 use std;
 use std::convert::TryInto;
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::runtime::Runtime;
-
-use futures::Future;
+use futures::future::{BoxFuture, FutureExt};
 
 use crate::core::{Failure, TypeId};
 use crate::handles::maybe_drop_handles;
+use crate::heap::HeapProfiler;
 use crate::nodes::{NodeKey, WrappedNode};
+use crate::runtime::{self, JoinHandle};
 use crate::scheduler::Session;
 use crate::tasks::{Rule, Tasks};
 use crate::types::Types;
-use boxfuture::{BoxFuture, Boxable};
 use core::clone::Clone;
 use fs::{safe_create_dir_all_ioerror, PosixFS};
 use graph::{EntryId, Graph, NodeContext};
-use parking_lot::RwLock;
+use parking_lot::Mutex;
 use process_execution::{self, BoundedCommandRunner, CommandRunner};
 use rand::seq::SliceRandom;
 use reqwest;
@@ -29,6 +29,12 @@ use rule_graph::RuleGraph;
 use std::collections::btree_map::BTreeMap;
 use store::Store;
 
+///
+/// A type-erased Future resolving to a Node's typed result or a `Failure`, as used throughout the
+/// Node/Graph integration.
+///
+pub type NodeFuture<T> = BoxFuture<'static, Result<T, Failure>>;
+
 ///
 /// The core context shared (via Arc) between the Scheduler and the Context objects of
 /// all running Nodes.
@@ -42,7 +48,8 @@ pub struct Core {
   pub tasks: Tasks,
   pub rule_graph: RuleGraph<Rule>,
   pub types: Types,
-  runtime: Arc<RwLock<Runtime>>,
+  runtime: runtime::Runtime,
+  heap_profiler: Mutex<Option<HeapProfiler>>,
   store: Store,
   pub command_runner: BoundedCommandRunner,
   pub http_client: reqwest::r#async::Client,
@@ -72,15 +79,13 @@ impl Core {
     remote_execution_extra_platform_properties: BTreeMap<String, String>,
     process_execution_parallelism: usize,
     process_execution_cleanup_local_dirs: bool,
+    heap_profiling: bool,
   ) -> Core {
     // Randomize CAS address order to avoid thundering herds from common config.
     let mut remote_store_servers = remote_store_servers;
     remote_store_servers.shuffle(&mut rand::thread_rng());
 
-    let runtime =
-      Arc::new(RwLock::new(Runtime::new().unwrap_or_else(|e| {
-        panic!("Could not initialize Runtime: {:?}", e)
-      })));
+    let runtime = runtime::Runtime::new();
     // We re-use these certs for both the execution and store service; they're generally tied together.
     let root_ca_certs = if let Some(path) = remote_root_ca_certs_path {
       Some(
@@ -151,12 +156,19 @@ impl Core {
     let http_client = reqwest::r#async::Client::new();
     let rule_graph = RuleGraph::new(tasks.as_map(), root_subject_types);
 
+    let heap_profiler = Mutex::new(if heap_profiling {
+      Some(HeapProfiler::new())
+    } else {
+      None
+    });
+
     Core {
       graph: Graph::new(),
       tasks: tasks,
       rule_graph: rule_graph,
       types: types,
       runtime: runtime,
+      heap_profiler,
       store,
       command_runner,
       http_client,
@@ -174,48 +186,62 @@ impl Core {
   }
 
   ///
-  /// Start running a Future on a tokio Runtime.
+  /// Start running a Future on this Core's Runtime, returning a handle that can be awaited for
+  /// its result, or dropped to cancel it. See `runtime::Runtime::spawn` for how the task is
+  /// identified in `tracing` spans and logs.
   ///
-  pub fn spawn<F: Future<Item = (), Error = ()> + Send + 'static>(&self, future: F) {
-    // Make sure to copy our (thread-local) logging destination into the task.
-    // When a daemon thread kicks off a future, it should log like a daemon thread (and similarly
-    // for a user-facing thread).
-    let logging_destination = logging::get_destination();
-    self
-      .runtime
-      .read()
-      .executor()
-      .spawn(futures::future::ok(()).and_then(move |()| {
-        logging::set_destination(logging_destination);
-        future
-      }))
+  #[track_caller]
+  pub fn spawn<T, F>(&self, future: F) -> JoinHandle<T>
+  where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+  {
+    self.runtime.spawn(future)
   }
 
   ///
-  /// Run a Future and return its resolved Result.
+  /// Spawn a `!Send` Future onto the `LocalSet` of the enclosing `block_on_local` call, which runs
+  /// on the thread that made that call: Core has no dedicated thread of its own for these tasks.
+  /// See `runtime::Runtime::spawn_local`.
+  ///
+  pub fn spawn_local<F: Future<Output = ()> + 'static>(&self, future: F) {
+    self.runtime.spawn_local(future)
+  }
+
   ///
-  /// This should never be called from in a Future context, or any context where anyone may want to
-  /// spawn something on the runtime using Core::spawn.
+  /// Drive the given Future (and any tasks it spawns via `spawn_local`) to completion, blocking
+  /// the calling thread. See `runtime::Runtime::block_on_local`.
   ///
-  pub fn block_on<
-    Item: Send + 'static,
-    Error: Send + 'static,
-    F: Future<Item = Item, Error = Error> + Send + 'static,
-  >(
-    &self,
-    future: F,
-  ) -> Result<Item, Error> {
-    // Make sure to copy our (thread-local) logging destination into the task.
-    // When a daemon thread kicks off a future, it should log like a daemon thread (and similarly
-    // for a user-facing thread).
-    let logging_destination = logging::get_destination();
-    self
-      .runtime
-      .write()
-      .block_on(futures::future::ok(()).and_then(move |()| {
-        logging::set_destination(logging_destination);
-        future
-      }))
+  pub fn block_on_local<F: Future<Output = ()> + 'static>(&self, future: F) {
+    self.runtime.block_on_local(future)
+  }
+
+  ///
+  /// Run a Future and return its resolved Output.
+  ///
+  /// Unlike with a bare `tokio::runtime::Runtime`, it is safe to call this from a context that is
+  /// already being driven by this Core's Runtime (e.g. from within a spawned Future): see
+  /// `runtime::Runtime::block_on`.
+  ///
+  #[track_caller]
+  pub fn block_on<T, F>(&self, future: F) -> T
+  where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+  {
+    self.runtime.block_on(future)
+  }
+
+  ///
+  /// Shut down this Core's Runtime, waiting up to `timeout` for outstanding work (CAS uploads,
+  /// local process cleanup, and the like) to finish before forcing a shutdown, and dumping a heap
+  /// allocation profile if one was requested via `heap_profiling` at construction time.
+  ///
+  pub fn shutdown(&self, timeout: Duration) -> Result<(), String> {
+    let result = self.runtime.shutdown(timeout);
+    // Dropping the profiler dumps its allocation profile.
+    self.heap_profiler.lock().take();
+    result
   }
 }
 
@@ -238,19 +264,19 @@ impl Context {
   ///
   /// Get the future value for the given Node implementation.
   ///
-  pub fn get<N: WrappedNode>(&self, node: N) -> BoxFuture<N::Item, Failure> {
+  pub fn get<N: WrappedNode>(&self, node: N) -> NodeFuture<N::Item> {
     // TODO: Odd place for this... could do it periodically in the background?
     maybe_drop_handles();
-    self
-      .core
-      .graph
-      .get(self.entry_id, self, node.into())
-      .map(|node_result| {
-        node_result
-          .try_into()
-          .unwrap_or_else(|_| panic!("A Node implementation was ambiguous."))
-      })
-      .to_boxed()
+    let context = self.clone();
+    async move {
+      let node_result = context
+        .core
+        .graph
+        .get(context.entry_id, &context, node.into())
+        .await?;
+      Ok(node_result.try_into()?)
+    }
+    .boxed()
   }
 }
 
@@ -273,10 +299,11 @@ impl NodeContext for Context {
     &self.core.graph
   }
 
+  #[track_caller]
   fn spawn<F>(&self, future: F)
   where
-    F: Future<Item = (), Error = ()> + Send + 'static,
+    F: Future<Output = ()> + Send + 'static,
   {
-    self.core.runtime.read().executor().spawn(future);
+    self.core.runtime.spawn_void(future);
   }
 }
@@ -0,0 +1,36 @@
+// Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//! An opt-in heap-profiling mode, gated behind the `dhat-heap` feature, for operators debugging
+//! the daemon's memory growth under large builds. When enabled, `dhat` tracks every allocation
+//! made while a `HeapProfiler` guard is held, and dumps an allocation profile (viewable at
+//! https://nnethercote.github.io/dh_view/dh_view.html) when that guard is dropped, which lets you
+//! see where bytes are being retained in the `Graph` and `Store` caches.
+
+#[cfg(feature = "dhat-heap")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+///
+/// A guard that, while held, causes allocations to be tracked via `dhat` so that an allocation
+/// profile is dumped when it is dropped (by `Core::shutdown`). Constructing one when the
+/// `dhat-heap` feature is not enabled is a no-op.
+///
+pub struct HeapProfiler {
+  #[cfg(feature = "dhat-heap")]
+  _profiler: dhat::Profiler,
+}
+
+impl HeapProfiler {
+  #[cfg(feature = "dhat-heap")]
+  pub fn new() -> HeapProfiler {
+    HeapProfiler {
+      _profiler: dhat::Profiler::new_heap(),
+    }
+  }
+
+  #[cfg(not(feature = "dhat-heap"))]
+  pub fn new() -> HeapProfiler {
+    HeapProfiler {}
+  }
+}
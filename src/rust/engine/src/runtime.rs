@@ -0,0 +1,344 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use tokio::runtime::Runtime as TokioRuntime;
+use tokio::task::LocalSet;
+use tracing::Instrument;
+
+// Monotonically increasing id assigned to every task spawned via `Runtime::spawn`/`block_on`, so
+// that a slow or stuck task observed in logs or tracing spans can be correlated back to the
+// `JoinHandle` (and thus the `NodeKey`) that launched it.
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// A handle to a Future spawned via `Runtime::spawn`.
+///
+/// Awaiting this Future waits for the spawned task to complete and yields its result. Dropping it
+/// (without having awaited it to completion) aborts the underlying task, so that a `Session` (or
+/// any other owner of a handle) can cancel background work -- like speculative CAS fetches or
+/// process launches -- without waiting for it to finish.
+///
+pub struct JoinHandle<T> {
+  task_id: usize,
+  inner: tokio::task::JoinHandle<T>,
+}
+
+impl<T> JoinHandle<T> {
+  ///
+  /// The id assigned to the spawned task, for correlating scheduler-level events (or log lines
+  /// emitted from inside the spawned Future) against the `tracing` span it runs in.
+  ///
+  pub fn id(&self) -> usize {
+    self.task_id
+  }
+
+  ///
+  /// Cancel the underlying task. This is also done implicitly on Drop.
+  ///
+  pub fn abort(&self) {
+    self.inner.abort();
+  }
+}
+
+impl<T: 'static> Future for JoinHandle<T> {
+  type Output = T;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<T> {
+    // Both fields are unconditionally `Unpin`, so `JoinHandle<T>` is auto-`Unpin` and there's no
+    // need for `unsafe`/`map_unchecked_mut` to get a pinned reference to `inner`.
+    let inner = Pin::new(&mut self.get_mut().inner);
+    inner.poll(cx).map(|res| match res {
+      Ok(item) => item,
+      Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+      Err(_) => panic!("A spawned task was unexpectedly cancelled."),
+    })
+  }
+}
+
+impl<T> Drop for JoinHandle<T> {
+  fn drop(&mut self) {
+    self.abort();
+  }
+}
+
+///
+/// A wrapper around a `tokio::runtime::Runtime` that centralizes the propagation of the
+/// (thread-local) logging destination onto tasks run via `spawn` and `block_on`, and that makes
+/// `block_on` safe to call re-entrantly. Tasks started via `spawn_void` do not get the caller's
+/// logging destination.
+///
+/// The wrapped `TokioRuntime` is `None` after `shutdown` has completed: shutting down is terminal,
+/// rather than something that quietly resurrects a fresh, unmanaged Runtime underneath callers who
+/// still hold a handle to this one.
+///
+#[derive(Clone)]
+pub struct Runtime {
+  runtime: Arc<RwLock<Option<TokioRuntime>>>,
+}
+
+impl Runtime {
+  const SHUTDOWN_PANIC_MESSAGE: &str =
+    "Attempted to use a Runtime which has already been shut down.";
+
+  pub fn new() -> Runtime {
+    Runtime {
+      runtime: Arc::new(RwLock::new(Some(
+        TokioRuntime::new().unwrap_or_else(|e| panic!("Could not initialize Runtime: {:?}", e)),
+      ))),
+    }
+  }
+
+  ///
+  /// Start running a Future on this Runtime, returning a handle that can be awaited for its
+  /// result, or dropped to cancel it.
+  ///
+  /// The task is wrapped in a `tracing` span recording the source location of this call (rather
+  /// than, say, the type name of the combined Future chain being spawned, which can be enormous
+  /// and is not useful for correlating a stuck task back to the code that launched it) plus a
+  /// monotonically-increasing task id, also available via `JoinHandle::id`.
+  ///
+  #[track_caller]
+  pub fn spawn<T, F>(&self, future: F) -> JoinHandle<T>
+  where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+  {
+    let task_id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let span = tracing::trace_span!("spawn", task_id, location = %Location::caller());
+
+    // Make sure to copy our (thread-local) logging destination into the task.
+    // When a daemon thread kicks off a future, it should log like a daemon thread (and similarly
+    // for a user-facing thread).
+    let logging_destination = logging::get_destination();
+    let inner = self
+      .runtime
+      .read()
+      .as_ref()
+      .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+      .spawn(
+        async move {
+          logging::set_destination(logging_destination);
+          future.await
+        }
+        .instrument(span),
+      );
+    JoinHandle { task_id, inner }
+  }
+
+  ///
+  /// Spawn a Future for its side effects, without tracking its result or providing a way to
+  /// cancel it.
+  ///
+  /// Like `spawn`, this records a `tracing` span keyed by the call site and a task id (see
+  /// `spawn`'s doc comment) -- this is the path that every ordinary `Node` future runs through
+  /// (via `Context::spawn`), so it needs the same correlation as `spawn`/`block_on` to make a
+  /// stuck task traceable back to its call site.
+  ///
+  #[track_caller]
+  pub fn spawn_void<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+    let task_id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let span = tracing::trace_span!("spawn_void", task_id, location = %Location::caller());
+    self
+      .runtime
+      .read()
+      .as_ref()
+      .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+      .spawn(future.instrument(span));
+  }
+
+  ///
+  /// Spawn a `!Send` Future onto a `LocalSet`-driven thread.
+  ///
+  /// Futures that hold non-`Send` state -- a thread-local `PosixFS` directory handle, or a
+  /// non-`Send` FFI object crossing the Python bridge -- can be driven here without forcing that
+  /// state through `Send` bounds, while ordinary `Send` tasks spawned via `Runtime::spawn`
+  /// continue to make progress concurrently. Must be called from within a Future that is itself
+  /// running under `Runtime::block_on_local`.
+  ///
+  pub fn spawn_local<F: Future<Output = ()> + 'static>(&self, future: F) {
+    tokio::task::spawn_local(future);
+  }
+
+  ///
+  /// Drive the given Future (and any tasks it spawns via `spawn_local`) to completion on a
+  /// `LocalSet`, blocking the calling thread until it resolves.
+  ///
+  /// The `LocalSet` groups its tasks onto the one thread that calls this method, which is what
+  /// allows them to hold non-`Send` state. It is driven via
+  /// `Runtime::block_on(local_set.run_until(future))`, so regular `Send` tasks spawned via
+  /// `Runtime::spawn` keep making progress on this Runtime in the meantime.
+  ///
+  /// Like `block_on`, this is safe to call from a context that is already being driven by a
+  /// Runtime on the current thread: a nested call uses `block_in_place` rather than re-entering
+  /// `Runtime::block_on`, which would otherwise panic. This matters here specifically because
+  /// `spawn_local`/`block_on_local` exist to run Node futures holding non-`Send` state, which is
+  /// exactly the kind of work that tends to happen from inside a Future already spawned via
+  /// `Core::spawn`/`block_on`.
+  ///
+  pub fn block_on_local<F: Future<Output = ()> + 'static>(&self, future: F) {
+    let local_set = LocalSet::new();
+    if tokio::runtime::Handle::try_current().is_ok() {
+      tokio::task::block_in_place(|| {
+        self
+          .runtime
+          .read()
+          .as_ref()
+          .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+          .handle()
+          .block_on(local_set.run_until(future))
+      });
+      return;
+    }
+    self
+      .runtime
+      .read()
+      .as_ref()
+      .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+      .block_on(local_set.run_until(future));
+  }
+
+  ///
+  /// Run a Future and return its resolved Output.
+  ///
+  /// Unlike a bare `tokio::runtime::Runtime::block_on`, this is safe to call from a context that
+  /// is already being driven by a Runtime (this one, or any other on the current thread): rather
+  /// than re-entering `block_on` and panicking, a nested call instead uses `block_in_place` to
+  /// hand this worker thread's other tasks off to another worker while cooperatively blocking on
+  /// `future` here.
+  ///
+  /// Like `spawn`, this records a `tracing` span keyed by the call site and a task id (see
+  /// `spawn`'s doc comment).
+  ///
+  #[track_caller]
+  pub fn block_on<T, F>(&self, future: F) -> T
+  where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+  {
+    let task_id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let span = tracing::trace_span!("block_on", task_id, location = %Location::caller());
+    let _guard = span.enter();
+
+    if tokio::runtime::Handle::try_current().is_ok() {
+      return tokio::task::block_in_place(|| {
+        self
+          .runtime
+          .read()
+          .as_ref()
+          .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+          .handle()
+          .block_on(future)
+      });
+    }
+
+    // Make sure to copy our (thread-local) logging destination into the task.
+    // When a daemon thread kicks off a future, it should log like a daemon thread (and similarly
+    // for a user-facing thread).
+    let logging_destination = logging::get_destination();
+    self
+      .runtime
+      .read()
+      .as_ref()
+      .unwrap_or_else(|| panic!("{}", Self::SHUTDOWN_PANIC_MESSAGE))
+      .block_on(async move {
+        logging::set_destination(logging_destination);
+        future.await
+      })
+  }
+
+  ///
+  /// Drain and tear down this Runtime, waiting up to `timeout` for outstanding work (CAS uploads,
+  /// local process cleanup, and the like) to finish on its own before forcing a shutdown.
+  ///
+  /// This is terminal: afterwards, `spawn`/`block_on`/`block_on_local` on this (cloned) Runtime
+  /// panic rather than silently running against a fresh, unmanaged Runtime that nothing else will
+  /// ever drain. Calling `shutdown` a second time is an error, rather than tearing down yet
+  /// another throwaway Runtime.
+  ///
+  /// Returns an error if the deadline was exceeded, in which case the shutdown was forced rather
+  /// than graceful.
+  ///
+  pub fn shutdown(&self, timeout: Duration) -> Result<(), String> {
+    let runtime = match self.runtime.write().take() {
+      Some(runtime) => runtime,
+      None => return Err("Runtime has already been shut down.".to_owned()),
+    };
+    // `shutdown_timeout` drains outstanding tasks until they finish or the deadline elapses,
+    // whichever comes first, and then forcibly aborts anything still outstanding. It has no
+    // return value indicating which of those happened, so race it against the deadline ourselves
+    // by timing the call: if it ran for (approximately) the full timeout, the drain didn't finish
+    // on its own and the shutdown was forced.
+    let started_at = Instant::now();
+    runtime.shutdown_timeout(timeout);
+    if started_at.elapsed() >= timeout {
+      Err(format!(
+        "Runtime did not become idle within {:?}: forced a shutdown.",
+        timeout
+      ))
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  use super::Runtime;
+
+  #[test]
+  fn join_handle_aborts_task_on_drop() {
+    let runtime = Runtime::new();
+    let ran = Arc::new(AtomicBool::new(false));
+
+    let ran2 = ran.clone();
+    let handle = runtime.spawn(async move {
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      ran2.store(true, Ordering::SeqCst);
+    });
+    drop(handle);
+
+    thread::sleep(Duration::from_millis(150));
+    assert!(
+      !ran.load(Ordering::SeqCst),
+      "dropping a JoinHandle should abort the underlying task before it completes"
+    );
+  }
+
+  #[test]
+  fn shutdown_errors_when_deadline_exceeded() {
+    let runtime = Runtime::new();
+    runtime.spawn_void(async {
+      tokio::time::sleep(Duration::from_millis(200)).await;
+    });
+
+    let result = runtime.shutdown(Duration::from_millis(10));
+    assert!(
+      result.is_err(),
+      "shutdown should report an error when outstanding work outlives the deadline"
+    );
+  }
+
+  #[test]
+  fn shutdown_is_terminal() {
+    let runtime = Runtime::new();
+    assert!(runtime.shutdown(Duration::from_secs(1)).is_ok());
+    assert!(
+      runtime.shutdown(Duration::from_secs(1)).is_err(),
+      "a second shutdown should error rather than tear down a fresh, throwaway runtime"
+    );
+  }
+}